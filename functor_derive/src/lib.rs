@@ -0,0 +1,225 @@
+//! A generalised, derivable `Functor`.
+//!
+//! `#[derive(Functor)]` implements [`Functor`] for a struct or enum by
+//! walking its fields and mapping a closure over every occurrence of the
+//! chosen generic parameter, recursing into `Vec`, `Option`, `HashMap`,
+//! `VecDeque`, arrays, tuples and `PhantomData`.
+//!
+//! The trait itself is only implemented for a derive over a single, unbounded
+//! parameter, without a suffix and without fields of a nested `Functor` type.
+//! Every other derive generates inherent methods of the same names (suffixed
+//! where a suffix is given) instead.
+
+pub use functor_derive_lib::Functor;
+
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+/// A type that is generic over `A` and knows how to map a function over
+/// every `A` it contains, producing the same shape with `A` replaced by `B`.
+pub trait Functor<A> {
+    /// `Self`, but with every occurrence of `A` replaced by `B`.
+    type Target<B>;
+
+    /// Maps `f` over every `A` contained in `self`, by value.
+    fn fmap<B>(self, f: impl Fn(A) -> B) -> Self::Target<B>
+    where
+        Self: Sized,
+    {
+        self.fmap_ref(&f)
+    }
+
+    /// Maps `f` over every `A` contained in `self`, by reference to the closure.
+    fn fmap_ref<B>(self, f: &impl Fn(A) -> B) -> Self::Target<B>;
+
+    /// Like [`Functor::fmap`], but `f` is fallible. Stops at the first error.
+    fn try_fmap<B, E>(self, f: impl Fn(A) -> Result<B, E>) -> Result<Self::Target<B>, E>
+    where
+        Self: Sized,
+    {
+        self.try_fmap_ref(&f)
+    }
+
+    /// Like [`Functor::fmap_ref`], but `f` is fallible. Stops at the first error.
+    fn try_fmap_ref<B, E>(self, f: &impl Fn(A) -> Result<B, E>) -> Result<Self::Target<B>, E>;
+
+    /// Like [`Functor::fmap`], but `f` is fallible and, unlike [`Functor::try_fmap`],
+    /// never stops at the first error: every `A` is still visited, and every
+    /// error produced along the way is accumulated into the returned `Vec`.
+    fn validate_fmap<B, E>(self, f: impl Fn(A) -> Result<B, E>) -> Result<Self::Target<B>, Vec<E>>
+    where
+        Self: Sized,
+    {
+        self.validate_fmap_ref(&f)
+    }
+
+    /// Like [`Functor::fmap_ref`], but `f` is fallible and, unlike
+    /// [`Functor::try_fmap_ref`], never stops at the first error: every `A`
+    /// is still visited, and every error produced along the way is
+    /// accumulated into the returned `Vec`.
+    fn validate_fmap_ref<B, E>(self, f: &impl Fn(A) -> Result<B, E>) -> Result<Self::Target<B>, Vec<E>>;
+}
+
+impl<A> Functor<A> for Vec<A> {
+    type Target<B> = Vec<B>;
+
+    fn fmap_ref<B>(self, f: &impl Fn(A) -> B) -> Vec<B> {
+        self.into_iter().map(f).collect()
+    }
+
+    fn try_fmap_ref<B, E>(self, f: &impl Fn(A) -> Result<B, E>) -> Result<Vec<B>, E> {
+        self.into_iter().map(f).collect()
+    }
+
+    fn validate_fmap_ref<B, E>(self, f: &impl Fn(A) -> Result<B, E>) -> Result<Vec<B>, Vec<E>> {
+        let mut values = Vec::with_capacity(self.len());
+        let mut errors = Vec::new();
+        for item in self {
+            match f(item) {
+                Ok(v) => values.push(v),
+                Err(e) => errors.push(e),
+            }
+        }
+        if errors.is_empty() {
+            Ok(values)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+impl<A> Functor<A> for VecDeque<A> {
+    type Target<B> = VecDeque<B>;
+
+    fn fmap_ref<B>(self, f: &impl Fn(A) -> B) -> VecDeque<B> {
+        self.into_iter().map(f).collect()
+    }
+
+    fn try_fmap_ref<B, E>(self, f: &impl Fn(A) -> Result<B, E>) -> Result<VecDeque<B>, E> {
+        self.into_iter().map(f).collect()
+    }
+
+    fn validate_fmap_ref<B, E>(self, f: &impl Fn(A) -> Result<B, E>) -> Result<VecDeque<B>, Vec<E>> {
+        let mut values = VecDeque::with_capacity(self.len());
+        let mut errors = Vec::new();
+        for item in self {
+            match f(item) {
+                Ok(v) => values.push_back(v),
+                Err(e) => errors.push(e),
+            }
+        }
+        if errors.is_empty() {
+            Ok(values)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+impl<A> Functor<A> for Option<A> {
+    type Target<B> = Option<B>;
+
+    fn fmap_ref<B>(self, f: &impl Fn(A) -> B) -> Option<B> {
+        self.map(f)
+    }
+
+    fn try_fmap_ref<B, E>(self, f: &impl Fn(A) -> Result<B, E>) -> Result<Option<B>, E> {
+        self.map(f).transpose()
+    }
+
+    fn validate_fmap_ref<B, E>(self, f: &impl Fn(A) -> Result<B, E>) -> Result<Option<B>, Vec<E>> {
+        match self {
+            None => Ok(None),
+            Some(a) => match f(a) {
+                Ok(b) => Ok(Some(b)),
+                Err(e) => Err(vec![e]),
+            },
+        }
+    }
+}
+
+impl<K: Eq + Hash, A> Functor<A> for HashMap<K, A> {
+    type Target<B> = HashMap<K, B>;
+
+    fn fmap_ref<B>(self, f: &impl Fn(A) -> B) -> HashMap<K, B> {
+        self.into_iter().map(|(k, v)| (k, f(v))).collect()
+    }
+
+    fn try_fmap_ref<B, E>(self, f: &impl Fn(A) -> Result<B, E>) -> Result<HashMap<K, B>, E> {
+        self.into_iter().map(|(k, v)| Ok((k, f(v)?))).collect()
+    }
+
+    fn validate_fmap_ref<B, E>(self, f: &impl Fn(A) -> Result<B, E>) -> Result<HashMap<K, B>, Vec<E>> {
+        let mut values = HashMap::with_capacity(self.len());
+        let mut errors = Vec::new();
+        for (k, v) in self {
+            match f(v) {
+                Ok(b) => {
+                    values.insert(k, b);
+                }
+                Err(e) => errors.push(e),
+            }
+        }
+        if errors.is_empty() {
+            Ok(values)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+impl<A> Functor<A> for PhantomData<A> {
+    type Target<B> = PhantomData<B>;
+
+    fn fmap_ref<B>(self, _f: &impl Fn(A) -> B) -> PhantomData<B> {
+        PhantomData
+    }
+
+    fn try_fmap_ref<B, E>(self, _f: &impl Fn(A) -> Result<B, E>) -> Result<PhantomData<B>, E> {
+        Ok(PhantomData)
+    }
+
+    fn validate_fmap_ref<B, E>(self, _f: &impl Fn(A) -> Result<B, E>) -> Result<PhantomData<B>, Vec<E>> {
+        Ok(PhantomData)
+    }
+}
+
+impl<A, const N: usize> Functor<A> for [A; N] {
+    type Target<B> = [B; N];
+
+    fn fmap_ref<B>(self, f: &impl Fn(A) -> B) -> [B; N] {
+        self.map(f)
+    }
+
+    fn try_fmap_ref<B, E>(self, f: &impl Fn(A) -> Result<B, E>) -> Result<[B; N], E> {
+        let mut err = None;
+        let mapped = self.map(|a| match f(a) {
+            Ok(b) => Some(b),
+            Err(e) => {
+                err = Some(e);
+                None
+            }
+        });
+        match err {
+            Some(e) => Err(e),
+            None => Ok(mapped.map(Option::unwrap)),
+        }
+    }
+
+    fn validate_fmap_ref<B, E>(self, f: &impl Fn(A) -> Result<B, E>) -> Result<[B; N], Vec<E>> {
+        let mut errors = Vec::new();
+        let mapped = self.map(|a| match f(a) {
+            Ok(b) => Some(b),
+            Err(e) => {
+                errors.push(e);
+                None
+            }
+        });
+        if errors.is_empty() {
+            Ok(mapped.map(Option::unwrap))
+        } else {
+            Err(errors)
+        }
+    }
+}
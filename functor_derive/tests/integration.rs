@@ -2,7 +2,6 @@
 #![allow(dead_code)]
 
 use functor_derive::Functor;
-use functor_derive_lib::Functor;
 use std::any::{Any, TypeId};
 use std::collections::{HashMap, VecDeque};
 use std::marker::PhantomData;
@@ -21,7 +20,7 @@ fn struct_simple() {
     };
 
     assert_eq!(
-        x.fmap(&mut |x| x as u64).type_id(),
+        x.fmap(|x| x as u64).type_id(),
         TypeId::of::<StructSimple<u64>>()
     );
 }
@@ -42,7 +41,7 @@ fn struct_option() {
     };
 
     assert_eq!(
-        x.fmap(&mut |x| x as u64).type_id(),
+        x.fmap(|x| x as u64).type_id(),
         TypeId::of::<StructOption<u64>>()
     );
 }
@@ -61,7 +60,7 @@ fn struct_vec() {
     };
 
     assert_eq!(
-        x.fmap(&mut |x| x as u64).type_id(),
+        x.fmap(|x| x as u64).type_id(),
         TypeId::of::<StructVec<u64>>()
     );
 }
@@ -80,7 +79,7 @@ fn struct_vecdeque() {
     };
 
     assert_eq!(
-        x.fmap(&mut |x| x as u64).type_id(),
+        x.fmap(|x| x as u64).type_id(),
         TypeId::of::<StructVecDeque<u64>>()
     );
 }
@@ -99,7 +98,7 @@ fn struct_tuple_1() {
     };
 
     assert_eq!(
-        x.fmap(&mut |x| x as u64).type_id(),
+        x.fmap(|x| x as u64).type_id(),
         TypeId::of::<StructTuple<u64>>()
     );
 }
@@ -118,7 +117,7 @@ fn struct_tuple_2() {
     };
 
     assert_eq!(
-        x.fmap(&mut |x| x as u64).type_id(),
+        x.fmap(|x| x as u64).type_id(),
         TypeId::of::<StructTuple<u64>>()
     );
 }
@@ -137,7 +136,7 @@ fn struct_phantomdata() {
     };
 
     assert_eq!(
-        x.fmap(&mut |x| x as u64).type_id(),
+        x.fmap(|x| x as u64).type_id(),
         TypeId::of::<StructPhantomData<u64>>()
     );
 }
@@ -156,7 +155,7 @@ fn struct_hashmap() {
     };
 
     assert_eq!(
-        x.fmap(&mut |x| x as u64).type_id(),
+        x.fmap(|x| x as u64).type_id(),
         TypeId::of::<StructHashMap<u64>>()
     );
 }
@@ -175,7 +174,7 @@ fn struct_array_1() {
     };
 
     assert_eq!(
-        x.fmap(&mut |x| x as u64).type_id(),
+        x.fmap(|x| x as u64).type_id(),
         TypeId::of::<StructArray<u64>>()
     );
 }
@@ -194,7 +193,7 @@ fn struct_array_2() {
     };
 
     assert_eq!(
-        x.fmap(&mut |x| x as u64).type_id(),
+        x.fmap(|x| x as u64).type_id(),
         TypeId::of::<StructArray<u64>>()
     );
 }
@@ -213,7 +212,7 @@ fn struct_paren_1() {
     };
 
     assert_eq!(
-        x.fmap(&mut |x| x as u64).type_id(),
+        x.fmap(|x| x as u64).type_id(),
         TypeId::of::<StructArray<u64>>()
     );
 }
@@ -232,7 +231,7 @@ fn struct_paren_2() {
     };
 
     assert_eq!(
-        x.fmap(&mut |x| x as u64).type_id(),
+        x.fmap(|x| x as u64).type_id(),
         TypeId::of::<StructArray<u64>>()
     );
 }
@@ -249,7 +248,7 @@ fn enum_simple_tuple() {
     let x = EnumTuple::<usize>::Var1(18);
 
     assert_eq!(
-        x.fmap(&mut |x| x as u64).type_id(),
+        x.fmap(|x| x as u64).type_id(),
         TypeId::of::<EnumTuple<u64>>()
     );
 }
@@ -266,11 +265,234 @@ fn enum_simple_struct() {
     let x = EnumStruct::<usize>::Var1 { x: 18 };
 
     assert_eq!(
-        x.fmap(&mut |x| x as u64).type_id(),
+        x.fmap(|x| x as u64).type_id(),
         TypeId::of::<EnumStruct<u64>>()
     );
 }
 
+#[test]
+fn struct_nested_functor() {
+    #[derive(Functor)]
+    struct Inner<A> {
+        value: A,
+    }
+
+    #[derive(Functor)]
+    struct Outer<A> {
+        field_1: Inner<A>,
+        field_2: u32,
+    }
+
+    let x = Outer::<usize> {
+        field_1: Inner { value: 42 },
+        field_2: 13,
+    };
+
+    assert_eq!(
+        x.fmap(|x| x as u64).type_id(),
+        TypeId::of::<Outer<u64>>()
+    );
+}
+
+#[test]
+fn enum_combined_two_params() {
+    #[derive(Functor)]
+    #[functor((A, B) = ab)]
+    enum Either<A, B> {
+        Left(A),
+        Right(B),
+    }
+
+    let x = Either::<usize, bool>::Left(42);
+
+    assert_eq!(
+        x.fmap_ab(|a| a as u64, |b| !b).type_id(),
+        TypeId::of::<Either<u64, bool>>()
+    );
+}
+
+#[test]
+fn struct_combined_two_params() {
+    #[derive(Functor)]
+    #[functor((A, B) = ab)]
+    struct Pair<A, B> {
+        left: A,
+        right: (Vec<B>, u8),
+    }
+
+    let x = Pair::<usize, bool> {
+        left: 42,
+        right: (vec![true, false], 13),
+    };
+
+    assert_eq!(
+        x.fmap_ab(|a| a as u64, |b| !b).type_id(),
+        TypeId::of::<Pair<u64, bool>>()
+    );
+}
+
+#[test]
+fn struct_combined_two_params_try_fmap() {
+    #[derive(Functor, Debug)]
+    #[functor((A, B) = ab)]
+    struct Pair<A, B> {
+        left: A,
+        right: (Vec<B>, u8),
+    }
+
+    let x = Pair::<usize, bool> {
+        left: 42,
+        right: (vec![true, false], 13),
+    };
+
+    let ok: Result<Pair<u64, bool>, &'static str> =
+        x.try_fmap_ab(|a| Ok(a as u64), |b| Ok(!b));
+    assert_eq!(ok.unwrap().left, 42);
+
+    let x = Pair::<usize, bool> {
+        left: 42,
+        right: (vec![true, false], 13),
+    };
+
+    let err: Result<Pair<u64, bool>, &'static str> =
+        x.try_fmap_ab(|a| Ok(a as u64), |_| Err("no bools allowed"));
+    assert_eq!(err.unwrap_err(), "no bools allowed");
+}
+
+#[test]
+fn struct_hygienic_against_user_generic_named_like_generated_idents() {
+    #[derive(Functor)]
+    struct StructHygiene<A, __B> {
+        field_1: A,
+        field_2: __B,
+    }
+
+    let x = StructHygiene::<usize, bool> {
+        field_1: 42,
+        field_2: true,
+    };
+
+    assert_eq!(
+        x.fmap(|x| x as u64).type_id(),
+        TypeId::of::<StructHygiene<u64, bool>>()
+    );
+}
+
+#[test]
+fn struct_hygienic_try_fmap() {
+    #[derive(Functor, Debug)]
+    struct StructHygiene<A, __B> {
+        field_1: A,
+        field_2: __B,
+    }
+
+    let x = StructHygiene::<usize, bool> {
+        field_1: 42,
+        field_2: true,
+    };
+
+    let y: Result<StructHygiene<u64, bool>, &'static str> = x.try_fmap(|a| Ok(a as u64));
+    assert_eq!(y.unwrap().field_1, 42);
+
+    let x = StructHygiene::<usize, bool> {
+        field_1: 42,
+        field_2: true,
+    };
+
+    let err: Result<StructHygiene<u64, bool>, &'static str> = x.try_fmap(|_| Err("nope"));
+    assert_eq!(err.unwrap_err(), "nope");
+}
+
+#[test]
+fn struct_skip_field() {
+    #[derive(Functor)]
+    struct StructSkip<A> {
+        field_1: A,
+        #[functor(skip)]
+        field_2: String,
+    }
+
+    let x = StructSkip::<usize> {
+        field_1: 1,
+        field_2: "kept".to_string(),
+    };
+
+    let y = x.fmap(|a| a as u64);
+
+    assert_eq!(y.field_1, 1);
+    assert_eq!(y.field_2, "kept");
+}
+
+#[test]
+fn struct_map_with_field() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn counting_map<A, B>(value: A, f: &impl Fn(A) -> B) -> B {
+        CALLS.fetch_add(1, Ordering::SeqCst);
+        f(value)
+    }
+
+    #[derive(Functor)]
+    struct StructMapWith<A> {
+        #[functor(map_with = counting_map)]
+        field_1: A,
+        field_2: A,
+    }
+
+    let x = StructMapWith::<usize> {
+        field_1: 1,
+        field_2: 2,
+    };
+
+    let y = x.fmap(|a| a as u64);
+
+    assert_eq!(y.field_1, 1);
+    assert_eq!(y.field_2, 2);
+    assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn struct_validate_fmap_success() {
+    #[derive(Functor)]
+    struct StructValidate<A> {
+        field_1: A,
+        field_2: Vec<A>,
+    }
+
+    let x = StructValidate::<i32> {
+        field_1: 4,
+        field_2: vec![2, 4, 6],
+    };
+
+    let y = x
+        .validate_fmap(|a| if a % 2 == 0 { Ok(a as u64) } else { Err(a) })
+        .unwrap();
+
+    assert_eq!(y.field_1, 4);
+    assert_eq!(y.field_2, vec![2, 4, 6]);
+}
+
+#[test]
+fn struct_validate_fmap_accumulates_errors() {
+    #[derive(Functor, Debug)]
+    struct StructValidate<A> {
+        field_1: A,
+        field_2: Vec<A>,
+    }
+
+    let x = StructValidate::<i32> {
+        field_1: 3,
+        field_2: vec![2, 5, 7],
+    };
+
+    let errors = x
+        .validate_fmap(|a| if a % 2 == 0 { Ok(a as u64) } else { Err(a) })
+        .unwrap_err();
+
+    assert_eq!(errors, vec![3, 5, 7]);
+}
+
 #[test]
 fn enum_simple_mixed() {
     #[derive(Functor)]
@@ -283,7 +505,7 @@ fn enum_simple_mixed() {
     let x = EnumMixed::<usize>::Var1 { x: 18 };
 
     assert_eq!(
-        x.fmap(&mut |x| x as u64).type_id(),
+        x.fmap(|x| x as u64).type_id(),
         TypeId::of::<EnumMixed<u64>>()
     );
 }
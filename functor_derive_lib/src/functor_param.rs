@@ -0,0 +1,147 @@
+use proc_macro2::Ident;
+use proc_macro_error::abort_call_site;
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
+use syn::{parenthesized, Attribute as SynAttribute, DeriveInput, GenericParam, Meta, Path, Token};
+
+/// What the user asked us to derive `Functor` over, as parsed from the
+/// `#[functor(...)]` attribute (or defaulted to the first generic type
+/// parameter when the attribute is absent).
+pub enum Attribute {
+    /// Derive a single, unsuffixed set of `fmap`/`try_fmap`/`validate_fmap`
+    /// methods (and their `_ref` forms) over this parameter.
+    Single(Ident),
+    /// Derive one `fmap_<suffix>`/`try_fmap_<suffix>`/`validate_fmap_<suffix>`
+    /// set of methods (and their `_ref` forms) per entry. Each
+    /// entry names one or more parameters; an entry naming more than one
+    /// parameter (written `(A, B)`) is mapped simultaneously, by a single
+    /// method taking one closure per parameter, rather than independently.
+    Many(Vec<(Vec<Ident>, Ident)>),
+}
+
+/// Parses the `#[functor(...)]` attribute on the item itself, if present.
+///
+/// `#[functor(A)]` selects a single parameter. `#[functor(A = a, B = b)]`
+/// selects several, each independently getting its own suffixed set of
+/// methods. `#[functor((A, B) = ab)]` instead maps `A` and `B`
+/// *simultaneously*, producing a single `fmap_ab` taking two closures.
+pub fn functor_param_from_attrs(input: &DeriveInput) -> Option<Attribute> {
+    let attr = input.attrs.iter().find(|attr| attr.path().is_ident("functor"))?;
+
+    let Meta::List(list) = &attr.meta else {
+        abort_call_site!("Expected `#[functor(...)]`.");
+    };
+
+    let entries = list
+        .parse_args_with(Punctuated::<ParamEntry, Token![,]>::parse_terminated)
+        .unwrap_or_else(|err| abort_call_site!("Could not parse `#[functor(...)]`: {}", err));
+
+    let mut entries = entries.into_iter();
+    let first = entries.next().unwrap_or_else(|| abort_call_site!("`#[functor(...)]` needs at least one parameter."));
+
+    if first.suffix.is_none() && first.params.len() == 1 && entries.len() == 0 {
+        return Some(Attribute::Single(first.params.into_iter().next().unwrap()));
+    }
+
+    let to_entry = |entry: ParamEntry| {
+        let suffix = entry.suffix.unwrap_or_else(|| default_suffix(&entry.params));
+        (entry.params, suffix)
+    };
+
+    let mut params = vec![to_entry(first)];
+    params.extend(entries.map(to_entry));
+    Some(Attribute::Many(params))
+}
+
+/// When no explicit `= suffix` is given, a single-parameter entry defaults
+/// to its own name; a combined entry joins its parameters' names, lowercased,
+/// with `_`.
+fn default_suffix(params: &[Ident]) -> Ident {
+    use quote::format_ident;
+
+    match params {
+        [param] => param.clone(),
+        params => {
+            let joined = params.iter().map(|p| p.to_string().to_lowercase()).collect::<Vec<_>>().join("_");
+            format_ident!("{}", joined)
+        }
+    }
+}
+
+/// Falls back to the first generic type parameter of the item when no
+/// `#[functor(...)]` attribute was given.
+pub fn functor_param_first(input: &DeriveInput) -> Ident {
+    input
+        .generics
+        .params
+        .iter()
+        .find_map(|param| match param {
+            GenericParam::Type(typ) => Some(typ.ident.clone()),
+            _ => None,
+        })
+        .unwrap_or_else(|| abort_call_site!("`{}` has no generic type parameter to derive `Functor` over.", input.ident))
+}
+
+/// How an individual field's `#[functor(...)]` attribute, if any, overrides
+/// the derive's normal container-based dispatch for that field.
+pub enum FieldAttribute {
+    /// `#[functor(skip)]`: leave the field untouched. Only accepted on a
+    /// field whose type does not mention a mapped parameter; the derive
+    /// aborts with an error otherwise.
+    Skip,
+    /// `#[functor(map_with = path)]`: call `path` with the field and the
+    /// mapping closure(s) instead of dispatching on the field's type.
+    MapWith(Path),
+}
+
+/// Parses the `#[functor(...)]` attribute on a single field, if present.
+pub fn field_attribute_from_attrs(attrs: &[SynAttribute]) -> Option<FieldAttribute> {
+    let attr = attrs.iter().find(|attr| attr.path().is_ident("functor"))?;
+
+    let Meta::List(list) = &attr.meta else {
+        abort_call_site!("Expected `#[functor(...)]`.");
+    };
+
+    list.parse_args_with(FieldAttribute::parse)
+        .map(Some)
+        .unwrap_or_else(|err| abort_call_site!("Could not parse `#[functor(...)]`: {}", err))
+}
+
+impl Parse for FieldAttribute {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let keyword: Ident = input.parse()?;
+        match keyword.to_string().as_str() {
+            "skip" => Ok(FieldAttribute::Skip),
+            "map_with" => {
+                input.parse::<Token![=]>()?;
+                Ok(FieldAttribute::MapWith(input.parse()?))
+            }
+            other => abort_call_site!("Unknown field-level `#[functor(...)]` attribute `{}`; expected `skip` or `map_with = ...`.", other),
+        }
+    }
+}
+
+struct ParamEntry {
+    params: Vec<Ident>,
+    suffix: Option<Ident>,
+}
+
+impl Parse for ParamEntry {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let params = if input.peek(syn::token::Paren) {
+            let content;
+            parenthesized!(content in input);
+            Punctuated::<Ident, Token![,]>::parse_terminated(&content)?.into_iter().collect()
+        } else {
+            vec![input.parse()?]
+        };
+
+        let suffix = if input.peek(Token![=]) {
+            input.parse::<Token![=]>()?;
+            Some(input.parse()?)
+        } else {
+            None
+        };
+        Ok(ParamEntry { params, suffix })
+    }
+}
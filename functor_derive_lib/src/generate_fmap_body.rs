@@ -0,0 +1,198 @@
+use proc_macro2::{Ident, TokenStream};
+use proc_macro_error::abort_call_site;
+use quote::{format_ident, quote};
+use syn::{Data, Field, Fields, Type};
+
+use crate::functor_param::{field_attribute_from_attrs, FieldAttribute};
+use crate::generate_map::{generate_map, type_contains_param, Mode};
+
+/// Generates the body of `fmap_ref` (or `try_fmap_ref`/`validate_fmap_ref`,
+/// depending on `mode`; see [`Mode`]). `pairs` has one `(parameter, closure)`
+/// entry per simultaneously-mapped generic parameter (see
+/// [`crate::functor_param::Attribute::Many`]).
+///
+/// Also returns the set of field types that turned out to be nested
+/// `Functor`s (see [`crate::generate_map::generate_map`]), paired with the
+/// parameter each is a `Functor` over, so `generate_impl` can add the bounds
+/// they require to the impl's `where` clause.
+pub fn generate_fmap_body(data: &Data, def_name: &Ident, pairs: &[(Ident, Ident)], mode: Mode) -> (TokenStream, Vec<(Type, Ident)>) {
+    let mut bounds = Vec::new();
+    let body = match data {
+        Data::Struct(data) => {
+            let pattern = fields_pattern(&data.fields);
+            let construct = fields_construct(quote!(#def_name), &data.fields, pairs, mode, &mut bounds);
+            quote! {
+                {
+                    let #def_name #pattern = self;
+                    #construct
+                }
+            }
+        }
+        Data::Enum(data) => {
+            let arms = data
+                .variants
+                .iter()
+                .map(|variant| {
+                    let variant_name = &variant.ident;
+                    let path = quote!(#def_name::#variant_name);
+                    let pattern = fields_pattern(&variant.fields);
+                    let construct = fields_construct(path.clone(), &variant.fields, pairs, mode, &mut bounds);
+                    quote!(#path #pattern => { #construct })
+                })
+                .collect::<Vec<_>>();
+            quote! {
+                match self {
+                    #(#arms),*
+                }
+            }
+        }
+        Data::Union(_) => abort_call_site!("`Functor` cannot be derived for unions."),
+    };
+    (body, bounds)
+}
+
+/// The pattern used to destructure `self` (or an enum variant) into one
+/// binding per field, named after the field for named fields and `__0`,
+/// `__1`, ... for tuple fields.
+fn fields_pattern(fields: &Fields) -> TokenStream {
+    match fields {
+        Fields::Named(fields) => {
+            let names = fields.named.iter().map(|field| field.ident.as_ref().unwrap());
+            quote!({ #(#names),* })
+        }
+        Fields::Unnamed(fields) => {
+            let binds = (0..fields.unnamed.len()).map(|i| format_ident!("__{i}"));
+            quote!((#(#binds),*))
+        }
+        Fields::Unit => quote!(),
+    }
+}
+
+/// Reconstructs `def_path` from the bindings produced by [`fields_pattern`],
+/// mapping each pair's closure over its parameter's occurrences in each
+/// field's contents along the way. In `Mode::Validate`, this instead takes
+/// the accumulating path in [`fields_construct_validate`], since every field
+/// must be mapped (and its errors collected) before deciding whether to
+/// rebuild the value at all.
+fn fields_construct(def_path: TokenStream, fields: &Fields, pairs: &[(Ident, Ident)], mode: Mode, bounds: &mut Vec<(Type, Ident)>) -> TokenStream {
+    if mode == Mode::Validate {
+        return fields_construct_validate(def_path, fields, pairs, bounds);
+    }
+
+    match fields {
+        Fields::Named(fields) => {
+            let assignments = fields.named.iter().map(|field| {
+                let name = field.ident.as_ref().unwrap();
+                let mapped = field_expr(field, quote!(#name), pairs, mode, bounds);
+                quote!(#name: #mapped)
+            });
+            quote!(#def_path { #(#assignments),* })
+        }
+        Fields::Unnamed(fields) => {
+            let values = fields.unnamed.iter().enumerate().map(|(i, field)| {
+                let bind = format_ident!("__{i}");
+                field_expr(field, quote!(#bind), pairs, mode, bounds)
+            });
+            quote!(#def_path(#(#values),*))
+        }
+        Fields::Unit => def_path,
+    }
+}
+
+/// The `Mode::Validate` counterpart of [`fields_construct`]: every field is
+/// mapped (each producing a `Result<_, Vec<__E>>`, via [`field_expr`]) before
+/// any decision is made, their errors are merged into one `Vec`, and
+/// `def_path` is only reconstructed if that `Vec` came out empty.
+fn fields_construct_validate(def_path: TokenStream, fields: &Fields, pairs: &[(Ident, Ident)], bounds: &mut Vec<(Type, Ident)>) -> TokenStream {
+    let entries = match fields {
+        Fields::Named(fields) => fields
+            .named
+            .iter()
+            .map(|field| {
+                let name = field.ident.as_ref().unwrap().clone();
+                let result = field_expr(field, quote!(#name), pairs, Mode::Validate, bounds);
+                (name, result)
+            })
+            .collect::<Vec<_>>(),
+        Fields::Unnamed(fields) => fields
+            .unnamed
+            .iter()
+            .enumerate()
+            .map(|(i, field)| {
+                let bind = format_ident!("__{i}");
+                let result = field_expr(field, quote!(#bind), pairs, Mode::Validate, bounds);
+                (bind, result)
+            })
+            .collect::<Vec<_>>(),
+        Fields::Unit => Vec::new(),
+    };
+
+    if entries.is_empty() {
+        return quote!(Ok(#def_path));
+    }
+
+    let binds = entries.iter().map(|(bind, _)| bind).collect::<Vec<_>>();
+    let results = entries.iter().map(|(_, result)| result).collect::<Vec<_>>();
+
+    let construct = match fields {
+        Fields::Named(fields) => {
+            let names = fields.named.iter().map(|field| field.ident.as_ref().unwrap());
+            quote!(#def_path { #(#names: #binds.unwrap()),* })
+        }
+        Fields::Unnamed(_) => quote!(#def_path(#(#binds.unwrap()),*)),
+        Fields::Unit => unreachable!("handled above"),
+    };
+
+    quote! {
+        {
+            let mut __errors = Vec::new();
+            #(
+                let #binds = match #results {
+                    Ok(__v) => Some(__v),
+                    Err(mut __e) => {
+                        __errors.append(&mut __e);
+                        None
+                    }
+                };
+            )*
+            if __errors.is_empty() {
+                Ok(#construct)
+            } else {
+                Err(__errors)
+            }
+        }
+    }
+}
+
+/// Maps a single field's binding, honouring its `#[functor(...)]` override
+/// (see [`crate::functor_param::FieldAttribute`]) if present, and otherwise
+/// falling back to the normal type-driven dispatch in [`generate_map`].
+fn field_expr(field: &Field, binding: TokenStream, pairs: &[(Ident, Ident)], mode: Mode, bounds: &mut Vec<(Type, Ident)>) -> TokenStream {
+    match field_attribute_from_attrs(&field.attrs) {
+        Some(FieldAttribute::Skip) => {
+            if pairs.iter().any(|(param, _)| type_contains_param(&field.ty, param)) {
+                let ty = &field.ty;
+                abort_call_site!(
+                    "`#[functor(skip)]` can't be used on a field of type `{}`, since it mentions \
+                     the mapped parameter; the field's value would have to keep its old type in a \
+                     struct whose type parameter has changed. Use `#[functor(map_with = ...)]` \
+                     instead if you need a custom conversion for this field.",
+                    quote!(#ty),
+                );
+            }
+            match mode {
+                Mode::Validate => quote!(Ok(#binding)),
+                Mode::Map | Mode::TryMap => binding,
+            }
+        }
+        Some(FieldAttribute::MapWith(path)) => {
+            let closures = pairs.iter().map(|(_, closure)| quote!(&#closure));
+            match mode {
+                Mode::Map => quote!(#path(#binding, #(#closures),*)),
+                Mode::TryMap => quote!(#path(#binding, #(#closures),*)?),
+                Mode::Validate => quote!(#path(#binding, #(#closures),*).map_err(|__e| vec![__e])),
+            }
+        }
+        None => generate_map(&field.ty, binding, pairs, mode, bounds),
+    }
+}
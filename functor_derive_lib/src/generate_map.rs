@@ -0,0 +1,364 @@
+use proc_macro2::{Ident, TokenStream};
+use proc_macro_error::abort_call_site;
+use quote::{format_ident, quote};
+use syn::{GenericArgument, Path, PathArguments, PathSegment, Type, TypePath};
+
+/// Which flavour of mapping expression [`generate_map`] (and
+/// [`crate::generate_fmap_body::generate_fmap_body`]) should produce.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// `fmap`/`fmap_ref`: infallible, the expression has the mapped type directly.
+    Map,
+    /// `try_fmap`/`try_fmap_ref`: `f` is fallible and the expression returns
+    /// early (via `?` or `return Err(...)`) at the first error.
+    TryMap,
+    /// `validate_fmap`/`validate_fmap_ref`: `f` is fallible, but every error
+    /// is accumulated rather than stopping at the first one, so the
+    /// expression always has type `Result<_, Vec<E>>`.
+    Validate,
+}
+
+/// Whether `ty` syntactically mentions `param` anywhere inside it.
+pub fn type_contains_param(ty: &Type, param: &Ident) -> bool {
+    match ty {
+        Type::Path(TypePath { qself: None, path }) => path_contains_param(path, param),
+        Type::Tuple(tuple) => tuple.elems.iter().any(|elem| type_contains_param(elem, param)),
+        Type::Array(array) => type_contains_param(&array.elem, param),
+        Type::Paren(paren) => type_contains_param(&paren.elem, param),
+        Type::Reference(reference) => type_contains_param(&reference.elem, param),
+        _ => false,
+    }
+}
+
+fn path_contains_param(path: &Path, param: &Ident) -> bool {
+    if path.is_ident(param) {
+        return true;
+    }
+    path.segments.iter().any(|segment| match &segment.arguments {
+        PathArguments::AngleBracketed(args) => args.args.iter().any(|arg| match arg {
+            GenericArgument::Type(ty) => type_contains_param(ty, param),
+            _ => false,
+        }),
+        _ => false,
+    })
+}
+
+fn generic_type_arg(path: &Path, idx: usize) -> &Type {
+    let PathArguments::AngleBracketed(args) = &path.segments.last().unwrap().arguments else {
+        panic!("expected angle-bracketed generic arguments");
+    };
+    args.args
+        .iter()
+        .filter_map(|arg| match arg {
+            GenericArgument::Type(ty) => Some(ty),
+            _ => None,
+        })
+        .nth(idx)
+        .expect("expected a generic type argument at this position")
+}
+
+/// Generates an expression that maps each `(param, closure)` pair's closure
+/// over every occurrence of `param` found in `expr: ty`, recursing into
+/// `Vec`, `Option`, `HashMap`, `VecDeque`, arrays, tuples and `PhantomData`.
+/// Only a `HashMap`'s values are mapped; its keys are moved over unchanged,
+/// so a key type that mentions a tracked parameter yields generated code
+/// that does not type-check. For a single-parameter derive, `pairs` has exactly one entry; for a
+/// combined multi-parameter derive (see `#[functor((A, B))]`), it has one
+/// entry per simultaneously-mapped parameter, and a leaf's type determines
+/// which closure applies.
+///
+/// When none of the above recognize `ty`, but `ty` is a path type that
+/// mentions exactly one tracked parameter in exactly one of its generic
+/// arguments, we treat it as a nested type that itself derives `Functor`
+/// over that argument, and record the bound this requires in `bounds` so
+/// that `generate_impl` can add it to the impl's `where` clause.
+///
+/// `mode` picks the flavour of expression generated (see [`Mode`]). In
+/// `Mode::Map`, the expression has the mapped type directly. In
+/// `Mode::TryMap`, it returns early with the first error (via `?` or
+/// `return Err(...)`), and the caller
+/// is expected to be building the argument of an `Ok(...)` inside a function
+/// returning `Result<_, __E>`. In `Mode::Validate`, the expression always has
+/// type `Result<_, Vec<__E>>`, with every error accumulated rather than the
+/// first one short-circuiting the rest.
+pub fn generate_map(ty: &Type, expr: TokenStream, pairs: &[(Ident, Ident)], mode: Mode, bounds: &mut Vec<(Type, Ident)>) -> TokenStream {
+    if !pairs.iter().any(|(param, _)| type_contains_param(ty, param)) {
+        return match mode {
+            Mode::Validate => quote!(Ok(#expr)),
+            Mode::Map | Mode::TryMap => expr,
+        };
+    }
+
+    match ty {
+        Type::Path(TypePath { qself: None, path }) if pairs.iter().any(|(param, _)| path.is_ident(param)) => {
+            let (_, closure) = pairs.iter().find(|(param, _)| path.is_ident(param)).unwrap();
+            match mode {
+                Mode::Map => quote!(#closure(#expr)),
+                Mode::TryMap => quote!(#closure(#expr)?),
+                Mode::Validate => quote!(#closure(#expr).map_err(|__e| vec![__e])),
+            }
+        }
+        Type::Paren(paren) => generate_map(&paren.elem, expr, pairs, mode, bounds),
+        Type::Tuple(tuple) => {
+            let binds = (0..tuple.elems.len()).map(|i| format_ident!("__t{i}")).collect::<Vec<_>>();
+            let mapped = tuple
+                .elems
+                .iter()
+                .zip(&binds)
+                .map(|(elem_ty, bind)| generate_map(elem_ty, quote!(#bind), pairs, mode, bounds))
+                .collect::<Vec<_>>();
+            match mode {
+                Mode::Validate => quote! {
+                    {
+                        let (#(#binds),*,) = #expr;
+                        let mut __errors = Vec::new();
+                        #(
+                            let #binds = match #mapped {
+                                Ok(__v) => Some(__v),
+                                Err(mut __e) => {
+                                    __errors.append(&mut __e);
+                                    None
+                                }
+                            };
+                        )*
+                        if __errors.is_empty() {
+                            Ok((#(#binds.unwrap()),*,))
+                        } else {
+                            Err(__errors)
+                        }
+                    }
+                },
+                Mode::Map | Mode::TryMap => quote! {
+                    {
+                        let (#(#binds),*,) = #expr;
+                        (#(#mapped),*,)
+                    }
+                },
+            }
+        }
+        Type::Array(array) => {
+            let inner = generate_map(&array.elem, quote!(__x), pairs, mode, bounds);
+            match mode {
+                Mode::Map => quote!(#expr.map(|__x| #inner)),
+                Mode::TryMap => quote! {
+                    {
+                        let mut __err = None;
+                        let __mapped = #expr.map(|__x| match (|| -> Result<_, _> { Ok(#inner) })() {
+                            Ok(__v) => Some(__v),
+                            Err(__e) => {
+                                __err = Some(__e);
+                                None
+                            }
+                        });
+                        match __err {
+                            Some(__e) => return Err(__e),
+                            None => __mapped.map(Option::unwrap),
+                        }
+                    }
+                },
+                Mode::Validate => quote! {
+                    {
+                        let mut __errors = Vec::new();
+                        let __mapped = #expr.map(|__x| match #inner {
+                            Ok(__v) => Some(__v),
+                            Err(mut __e) => {
+                                __errors.append(&mut __e);
+                                None
+                            }
+                        });
+                        if __errors.is_empty() {
+                            Ok(__mapped.map(Option::unwrap))
+                        } else {
+                            Err(__errors)
+                        }
+                    }
+                },
+            }
+        }
+        Type::Path(TypePath { qself: None, path }) => {
+            let segment = path.segments.last().unwrap();
+            match segment.ident.to_string().as_str() {
+                "Vec" | "VecDeque" => {
+                    let inner_ty = generic_type_arg(path, 0);
+                    let inner = generate_map(inner_ty, quote!(__x), pairs, mode, bounds);
+                    match mode {
+                        Mode::Map => quote!(#expr.into_iter().map(|__x| #inner).collect()),
+                        Mode::TryMap => quote! {
+                            match #expr.into_iter().map(|__x| Ok(#inner)).collect::<Result<_, _>>() {
+                                Ok(__v) => __v,
+                                Err(__e) => return Err(__e),
+                            }
+                        },
+                        Mode::Validate => quote! {
+                            {
+                                let mut __values = Vec::new();
+                                let mut __errors = Vec::new();
+                                for __x in #expr {
+                                    match #inner {
+                                        Ok(__v) => __values.push(__v),
+                                        Err(mut __e) => __errors.append(&mut __e),
+                                    }
+                                }
+                                if __errors.is_empty() {
+                                    Ok(__values.into_iter().collect())
+                                } else {
+                                    Err(__errors)
+                                }
+                            }
+                        },
+                    }
+                }
+                "Option" => {
+                    let inner_ty = generic_type_arg(path, 0);
+                    let inner = generate_map(inner_ty, quote!(__x), pairs, mode, bounds);
+                    match mode {
+                        Mode::Map => quote!(#expr.map(|__x| #inner)),
+                        Mode::TryMap => quote! {
+                            match #expr.map(|__x| Ok(#inner)).transpose() {
+                                Ok(__v) => __v,
+                                Err(__e) => return Err(__e),
+                            }
+                        },
+                        Mode::Validate => quote!(#expr.map(|__x| #inner).transpose()),
+                    }
+                }
+                "HashMap" => {
+                    let inner_ty = generic_type_arg(path, 1);
+                    let inner = generate_map(inner_ty, quote!(__v), pairs, mode, bounds);
+                    match mode {
+                        Mode::Map => quote!(#expr.into_iter().map(|(__k, __v)| (__k, #inner)).collect()),
+                        Mode::TryMap => quote! {
+                            match #expr.into_iter().map(|(__k, __v)| Ok((__k, #inner))).collect::<Result<_, _>>() {
+                                Ok(__m) => __m,
+                                Err(__e) => return Err(__e),
+                            }
+                        },
+                        Mode::Validate => quote! {
+                            {
+                                let mut __values = ::std::collections::HashMap::new();
+                                let mut __errors = Vec::new();
+                                for (__k, __v) in #expr {
+                                    match #inner {
+                                        Ok(__m) => {
+                                            __values.insert(__k, __m);
+                                        }
+                                        Err(mut __e) => __errors.append(&mut __e),
+                                    }
+                                }
+                                if __errors.is_empty() {
+                                    Ok(__values)
+                                } else {
+                                    Err(__errors)
+                                }
+                            }
+                        },
+                    }
+                }
+                "PhantomData" => match mode {
+                    Mode::Map | Mode::TryMap => quote!({ let _ = #expr; ::std::marker::PhantomData }),
+                    Mode::Validate => quote!({ let _ = #expr; Ok(::std::marker::PhantomData) }),
+                },
+                _ => {
+                    let matched = pairs.iter().filter(|(param, _)| type_contains_param(ty, param)).collect::<Vec<_>>();
+                    let [(param, closure)] = matched[..] else {
+                        abort_call_site!(
+                            "Don't know how to map over `{}`; a nested type must mention exactly one \
+                             mapped parameter to be treated as a nested `Functor`.",
+                            quote!(#ty),
+                        );
+                    };
+                    if count_param_occurrences_in_args(path, param) != 1 {
+                        abort_call_site!(
+                            "Don't know how to map over the `{}` in a field of type `{}`; \
+                             a nested type must mention `{}` in exactly one of its generic arguments \
+                             to be treated as a nested `Functor`.",
+                            param,
+                            quote!(#ty),
+                            param,
+                        );
+                    }
+                    bounds.push((ty.clone(), param.clone()));
+                    match mode {
+                        Mode::Map => quote!(::functor_derive::Functor::fmap_ref(#expr, #closure)),
+                        Mode::TryMap => quote!(::functor_derive::Functor::try_fmap_ref(#expr, #closure)?),
+                        Mode::Validate => quote!(::functor_derive::Functor::validate_fmap_ref(#expr, #closure)),
+                    }
+                }
+            }
+        }
+        Type::Reference(reference) => {
+            abort_call_site!(
+                "Don't know how to map over a field of type `{}`; `#[derive(Functor)]` \
+                 maps `self` by value and can't produce a reference to a value it no \
+                 longer owns. Use `#[functor(skip)]` or `#[functor(map_with = ...)]` \
+                 for this field instead.",
+                quote!(#reference),
+            );
+        }
+        _ => match mode {
+            Mode::Validate => quote!(Ok(#expr)),
+            Mode::Map | Mode::TryMap => expr,
+        },
+    }
+}
+
+/// Replaces every occurrence of `param` in `ty` with `target`, turning a
+/// nested-`Functor` field's source type (e.g. `Inner<A>`) into the literal
+/// type its mapped value has (e.g. `Inner<__B>`). `generate_impl` uses this
+/// to state, as an associated-type equality bound, that `ty`'s `Target`
+/// *is* that literal type — without it, the compiler only knows `ty`
+/// implements `Functor`, not what its `Target` projects to, and can't unify
+/// the mapped field's type with the one the outer `Target` expects.
+pub fn type_substitute_param(ty: &Type, param: &Ident, target: &Ident) -> Type {
+    match ty {
+        Type::Path(TypePath { qself: None, path }) if path.is_ident(param) => {
+            Type::Path(TypePath { qself: None, path: Path::from(PathSegment::from(target.clone())) })
+        }
+        Type::Path(TypePath { qself, path }) => {
+            let mut path = path.clone();
+            for segment in &mut path.segments {
+                if let PathArguments::AngleBracketed(args) = &mut segment.arguments {
+                    for arg in &mut args.args {
+                        if let GenericArgument::Type(arg_ty) = arg {
+                            *arg_ty = type_substitute_param(arg_ty, param, target);
+                        }
+                    }
+                }
+            }
+            Type::Path(TypePath { qself: qself.clone(), path })
+        }
+        Type::Tuple(tuple) => {
+            let mut tuple = tuple.clone();
+            for elem in &mut tuple.elems {
+                *elem = type_substitute_param(elem, param, target);
+            }
+            Type::Tuple(tuple)
+        }
+        Type::Array(array) => {
+            let mut array = array.clone();
+            *array.elem = type_substitute_param(&array.elem, param, target);
+            Type::Array(array)
+        }
+        Type::Paren(paren) => {
+            let mut paren = paren.clone();
+            *paren.elem = type_substitute_param(&paren.elem, param, target);
+            Type::Paren(paren)
+        }
+        Type::Reference(reference) => {
+            let mut reference = reference.clone();
+            *reference.elem = type_substitute_param(&reference.elem, param, target);
+            Type::Reference(reference)
+        }
+        _ => ty.clone(),
+    }
+}
+
+fn count_param_occurrences_in_args(path: &Path, param: &Ident) -> usize {
+    let PathArguments::AngleBracketed(args) = &path.segments.last().unwrap().arguments else {
+        return 0;
+    };
+    args.args
+        .iter()
+        .filter(|arg| matches!(arg, GenericArgument::Type(ty) if type_contains_param(ty, param)))
+        .count()
+}
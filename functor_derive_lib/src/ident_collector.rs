@@ -0,0 +1,44 @@
+use std::collections::HashSet;
+
+use quote::format_ident;
+use syn::visit::Visit;
+use syn::{DeriveInput, Ident};
+
+/// Collects every identifier that `syn`'s visitor reaches in `input` (generic
+/// parameters, field names, field types, attribute paths, ...), so that
+/// generated code can pick fresh names that don't collide with them. The
+/// argument tokens of an attribute (e.g. the path in
+/// `#[functor(map_with = ...)]`) are not inspected.
+pub fn collect_idents(input: &DeriveInput) -> HashSet<String> {
+    let mut collector = IdentCollector::default();
+    collector.visit_derive_input(input);
+    collector.idents
+}
+
+/// Picks a fresh identifier starting from `base`, appending an increasing
+/// counter until the candidate doesn't collide with anything in `used`,
+/// then reserves it in `used` so later calls won't reuse it either.
+pub fn fresh_ident(base: &str, used: &mut HashSet<String>) -> Ident {
+    if used.insert(base.to_string()) {
+        return format_ident!("{}", base);
+    }
+    let mut counter = 2;
+    loop {
+        let candidate = format!("{base}{counter}");
+        if used.insert(candidate.clone()) {
+            return format_ident!("{}", candidate);
+        }
+        counter += 1;
+    }
+}
+
+#[derive(Default)]
+struct IdentCollector {
+    idents: HashSet<String>,
+}
+
+impl Visit<'_> for IdentCollector {
+    fn visit_ident(&mut self, ident: &Ident) {
+        self.idents.insert(ident.to_string());
+    }
+}
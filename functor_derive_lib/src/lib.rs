@@ -1,14 +1,18 @@
 #![doc = include_str!("../README.md")]
+use std::collections::HashSet;
+
 use proc_macro::TokenStream;
-use proc_macro2::Ident;
+use proc_macro2::{Ident, TokenStream as TokenStream2};
 use proc_macro_error::{abort_call_site, proc_macro_error};
 use quote::{format_ident, quote};
 use syn::{parse_macro_input, DeriveInput, Expr, ExprPath, GenericArgument, GenericParam, Path, PathSegment, Type, TypePath};
 use crate::functor_param::{Attribute, functor_param_from_attrs};
+use crate::generate_map::Mode;
 
 mod functor_param;
 mod generate_fmap_body;
 mod generate_map;
+mod ident_collector;
 
 #[proc_macro_derive(Functor, attributes(functor))]
 #[proc_macro_error]
@@ -18,38 +22,57 @@ pub fn derive(input: TokenStream) -> TokenStream {
     // Name of the Struct or Enum we are implementing the `Functor` trait for.
     let def_name = input.ident.clone();
 
-    // Get the parameter to be mapped. First looks in attributes and falls back to the first generic type parameter.
-    match functor_param_from_attrs(&input).unwrap_or_else(|| functor_param::functor_param_first(&input)) {
-        Attribute::Single(functor_param) => generate_impl(&input, &def_name, functor_param, None),
-        Attribute::Many(params) => {
+    // The identifiers already in use in the item (see
+    // `ident_collector::collect_idents`), so the names we pick for the mapped
+    // type parameters, the error type and the closure bindings don't collide
+    // with or shadow them.
+    let used_idents = ident_collector::collect_idents(&input);
+
+    // Get the parameter(s) to be mapped. First looks in attributes and falls back to the first generic type parameter.
+    match functor_param_from_attrs(&input).unwrap_or_else(|| Attribute::Single(functor_param::functor_param_first(&input))) {
+        Attribute::Single(functor_param) => generate_impl(&input, &def_name, vec![functor_param], None, &used_idents),
+        Attribute::Many(entries) => {
             let mut tokens = TokenStream::new();
-            for (functor_param, name_suffix) in params {
-                tokens.extend(generate_impl(&input, &def_name, functor_param, Some(name_suffix)))
+            for (functor_params, name_suffix) in entries {
+                tokens.extend(generate_impl(&input, &def_name, functor_params, Some(name_suffix), &used_idents))
             }
             tokens
         },
     }
 }
 
-fn generate_impl(input: &DeriveInput, def_name: &Ident, functor_param: Ident, name_suffix: Option<Ident>) -> TokenStream {
+/// Generates the `Functor` impl (or, for a suffixed or combined derive, a
+/// bespoke inherent impl) for `functor_params`, the one or more type
+/// parameters of `input` to map over simultaneously. `used_idents` is every
+/// identifier already in use in `input`, so we can name our own generated
+/// generics, closures and error type without colliding with any of them.
+fn generate_impl(input: &DeriveInput, def_name: &Ident, functor_params: Vec<Ident>, name_suffix: Option<Ident>, used_idents: &HashSet<String>) -> TokenStream {
 // Get the generic parameters *including* bounds and other attributes.
     let gen_params = input.generics.params.iter().cloned().collect::<Vec<_>>();
 
-    // Find the position and type of the generic parameter. Aborts if absent.
-    let (functor_param_idx, functor_param_type) = gen_params
+    // A private copy to reserve fresh names into as we allocate them below.
+    let mut used_idents = used_idents.clone();
+
+    // Find the position and type of each mapped generic parameter. Aborts if any are absent.
+    let matched = functor_params
         .iter()
-        .enumerate()
-        .find_map(|(idx, param)| match param {
-            GenericParam::Type(typ) if typ.ident == functor_param => Some((idx, typ)),
-            _ => None,
+        .map(|functor_param| {
+            gen_params
+                .iter()
+                .enumerate()
+                .find_map(|(idx, param)| match param {
+                    GenericParam::Type(typ) if typ.ident == *functor_param => Some((idx, typ)),
+                    _ => None,
+                })
+                .unwrap_or_else(|| {
+                    abort_call_site!(
+                        "The generic parameter `{}` could not be found in the definition of `{}`.",
+                        functor_param,
+                        def_name
+                    )
+                })
         })
-        .unwrap_or_else(|| {
-            abort_call_site!(
-                "The generic parameter `{}` could not be found in the definition of `{}`.",
-                functor_param,
-                def_name
-            )
-        });
+        .collect::<Vec<_>>();
 
     // Maps the generic parameters to generic arguments for the source.
     let source_args = gen_params
@@ -68,63 +91,167 @@ fn generate_impl(input: &DeriveInput, def_name: &Ident, functor_param: Ident, na
         })
         .collect::<Vec<_>>();
 
-    // Create generic arguments for the target. We use `__B` for the mapped generic.
+    // The mapped generic and closure parameter for each functor parameter,
+    // and the error type for the fallible methods, all picked fresh so they
+    // can't collide with anything the user wrote.
+    let target_idents = functor_params.iter().map(|_| ident_collector::fresh_ident("__B", &mut used_idents)).collect::<Vec<_>>();
+    let closure_idents = functor_params.iter().map(|_| ident_collector::fresh_ident("__f", &mut used_idents)).collect::<Vec<_>>();
+    let error_ident = ident_collector::fresh_ident("__E", &mut used_idents);
+
+    // Create generic arguments for the target, replacing each mapped parameter with its `target_idents` entry.
     let mut target_args = source_args.clone();
-    target_args[functor_param_idx] = GenericArgument::Type(Type::Path(TypePath {
-        qself: None,
-        path: Path::from(PathSegment::from(format_ident!("__B"))),
-    }));
-
-    // Generate body of the `fmap` implementation.
-    let fmap_body =
-        generate_fmap_body::generate_fmap_body(&input.data, &def_name, &functor_param, false);
-    let try_fmap_body =
-        generate_fmap_body::generate_fmap_body(&input.data, &def_name, &functor_param, true);
-
-    // If there are no bounds on the generics, generate tokens for `Functor` trait impl for the given definition.
-    // Otherwise, generate `fmap` impl for the given definition.
-    if functor_param_type.bounds.is_empty() && name_suffix.is_none() {
+    for (i, (idx, _)) in matched.iter().enumerate() {
+        target_args[*idx] = GenericArgument::Type(Type::Path(TypePath {
+            qself: None,
+            path: Path::from(PathSegment::from(target_idents[i].clone())),
+        }));
+    }
+
+    let pairs = functor_params
+        .iter()
+        .cloned()
+        .zip(closure_idents.iter().cloned())
+        .collect::<Vec<_>>();
+
+    // Generate body of the `fmap` implementation. Fields whose type is some
+    // other generic type that itself carries a mapped parameter (and so must
+    // itself implement `Functor`) are collected into bounds alongside each
+    // mode's body. Each mode's bounds are kept separate (rather than merged
+    // into one impl-level `where` clause): the equality bound that makes a
+    // nested `Functor` field's mapped type line up with the outer `Target`
+    // (see [`generate_map::type_substitute_param`]) has to mention that mode's own
+    // `#target` generic, which only exists inside that method's own
+    // signature, so the bound has to live in that method's own `where`
+    // clause rather than the impl's.
+    let (fmap_body, mut fmap_bounds) =
+        generate_fmap_body::generate_fmap_body(&input.data, &def_name, &pairs, Mode::Map);
+    let (try_fmap_body, mut try_fmap_bounds) =
+        generate_fmap_body::generate_fmap_body(&input.data, &def_name, &pairs, Mode::TryMap);
+    let (validate_fmap_body, mut validate_fmap_bounds) =
+        generate_fmap_body::generate_fmap_body(&input.data, &def_name, &pairs, Mode::Validate);
+    dedup_bounds(&mut fmap_bounds);
+    dedup_bounds(&mut try_fmap_bounds);
+    dedup_bounds(&mut validate_fmap_bounds);
+    let has_nested_bounds = !fmap_bounds.is_empty() || !try_fmap_bounds.is_empty() || !validate_fmap_bounds.is_empty();
+
+    let fmap_where = nested_bound_where_clause(&fmap_bounds, &functor_params, &target_idents);
+    let try_fmap_where = nested_bound_where_clause(&try_fmap_bounds, &functor_params, &target_idents);
+    let validate_fmap_where = nested_bound_where_clause(&validate_fmap_bounds, &functor_params, &target_idents);
+
+    // A plain, single-parameter, unsuffixed derive with no nested `Functor`
+    // fields can implement the `Functor` trait directly. Everything else
+    // (bounded, suffixed, combined over several parameters, or containing a
+    // nested `Functor` field) gets a bespoke inherent impl instead, since
+    // only inherent methods can carry the per-method `where` clause a nested
+    // bound needs (a trait impl's methods can't add bounds beyond the
+    // trait's own declaration).
+    if functor_params.len() == 1 && matched[0].1.bounds.is_empty() && name_suffix.is_none() && !has_nested_bounds {
+        let functor_param = &functor_params[0];
+        let target = &target_idents[0];
+        let closure = &closure_idents[0];
         quote!(
             impl<#(#gen_params),*> ::functor_derive::Functor<#functor_param> for #def_name<#(#source_args),*> {
-                type Target<__B> = #def_name<#(#target_args),*>;
+                type Target<#target> = #def_name<#(#target_args),*>;
 
-                fn fmap_ref<__B>(self, __f: &impl Fn(#functor_param) -> __B) -> #def_name<#(#target_args),*> {
+                fn fmap_ref<#target>(self, #closure: &impl Fn(#functor_param) -> #target) -> #def_name<#(#target_args),*> {
                     #fmap_body
                 }
 
-                fn try_fmap_ref<__B, __E>(self, __f: &impl Fn(#functor_param) -> Result<__B, __E>) -> Result<#def_name<#(#target_args),*>, __E> {
+                fn try_fmap_ref<#target, #error_ident>(self, #closure: &impl Fn(#functor_param) -> Result<#target, #error_ident>) -> Result<#def_name<#(#target_args),*>, #error_ident> {
                     Ok(#try_fmap_body)
                 }
+
+                fn validate_fmap_ref<#target, #error_ident>(self, #closure: &impl Fn(#functor_param) -> Result<#target, #error_ident>) -> Result<#def_name<#(#target_args),*>, Vec<#error_ident>> {
+                    #validate_fmap_body
+                }
             }
         )
     } else {
-        let bounds = &functor_param_type.bounds;
-
         let suffix = name_suffix.map(|name_suffix| format!("_{name_suffix}")).unwrap_or_default();
         let fmap = format_ident!("fmap{suffix}");
         let fmap_ref = format_ident!("fmap_ref{suffix}");
         let try_fmap = format_ident!("try_fmap{suffix}");
         let try_fmap_ref = format_ident!("try_fmap_ref{suffix}");
+        let validate_fmap = format_ident!("validate_fmap{suffix}");
+        let validate_fmap_ref = format_ident!("validate_fmap_ref{suffix}");
+
+        // One `target: bounds` generic and one closure parameter per mapped parameter.
+        let target_generics = matched.iter().enumerate().map(|(i, (_, typ))| {
+            let target = &target_idents[i];
+            let bounds = &typ.bounds;
+            quote!(#target: #bounds)
+        }).collect::<Vec<_>>();
+        let closure_params = pairs.iter().enumerate().map(|(i, (param, closure))| {
+            let target = &target_idents[i];
+            quote!(#closure: impl Fn(#param) -> #target)
+        }).collect::<Vec<_>>();
+        let closure_ref_params = pairs.iter().enumerate().map(|(i, (param, closure))| {
+            let target = &target_idents[i];
+            quote!(#closure: &impl Fn(#param) -> #target)
+        }).collect::<Vec<_>>();
+        let try_closure_params = pairs.iter().enumerate().map(|(i, (param, closure))| {
+            let target = &target_idents[i];
+            quote!(#closure: impl Fn(#param) -> Result<#target, #error_ident>)
+        }).collect::<Vec<_>>();
+        let try_closure_ref_params = pairs.iter().enumerate().map(|(i, (param, closure))| {
+            let target = &target_idents[i];
+            quote!(#closure: &impl Fn(#param) -> Result<#target, #error_ident>)
+        }).collect::<Vec<_>>();
+        let closure_args = pairs.iter().map(|(_, closure)| quote!(&#closure)).collect::<Vec<_>>();
 
         quote!(
             impl<#(#gen_params),*> #def_name<#(#source_args),*> {
-                pub fn #fmap<__B: #bounds>(self, __f: impl Fn(#functor_param) -> __B) -> #def_name<#(#target_args),*> {
-                    self.fmap_ref(&__f)
+                pub fn #fmap<#(#target_generics),*>(self, #(#closure_params),*) -> #def_name<#(#target_args),*> #fmap_where {
+                    self.#fmap_ref(#(#closure_args),*)
                 }
 
-                pub fn #fmap_ref<__B: #bounds>(self, __f: &impl Fn(#functor_param) -> __B) -> #def_name<#(#target_args),*> {
+                pub fn #fmap_ref<#(#target_generics),*>(self, #(#closure_ref_params),*) -> #def_name<#(#target_args),*> #fmap_where {
                     #fmap_body
                 }
 
-                pub fn #try_fmap<__B: #bounds, __E>(self, __f: impl Fn(#functor_param) -> Result<__B, __E>) -> Result<#def_name<#(#target_args),*>, __E> {
-                    self.try_fmap_ref(&__f)
+                pub fn #try_fmap<#(#target_generics),*, #error_ident>(self, #(#try_closure_params),*) -> Result<#def_name<#(#target_args),*>, #error_ident> #try_fmap_where {
+                    self.#try_fmap_ref(#(#closure_args),*)
                 }
 
-                pub fn #try_fmap_ref<__B: #bounds, __E>(self, __f: &impl Fn(#functor_param) -> Result<__B, __E>) -> Result<#def_name<#(#target_args),*>, __E> {
+                pub fn #try_fmap_ref<#(#target_generics),*, #error_ident>(self, #(#try_closure_ref_params),*) -> Result<#def_name<#(#target_args),*>, #error_ident> #try_fmap_where {
                     Ok(#try_fmap_body)
                 }
+
+                pub fn #validate_fmap<#(#target_generics),*, #error_ident>(self, #(#try_closure_params),*) -> Result<#def_name<#(#target_args),*>, Vec<#error_ident>> #validate_fmap_where {
+                    self.#validate_fmap_ref(#(#closure_args),*)
+                }
+
+                pub fn #validate_fmap_ref<#(#target_generics),*, #error_ident>(self, #(#try_closure_ref_params),*) -> Result<#def_name<#(#target_args),*>, Vec<#error_ident>> #validate_fmap_where {
+                    #validate_fmap_body
+                }
             }
         )
     }.into()
 }
 
+/// Builds the `where` clause for one generated method from its nested
+/// `Functor` bounds, stating for each one not just that the nested type
+/// implements `Functor`, but what its `Target` actually is (see
+/// [`generate_map::type_substitute_param`]) — otherwise the compiler has no way
+/// to know that e.g. `<Inner<A> as Functor<A>>::Target<__B>` is the same
+/// type as the literal `Inner<__B>` the outer `Target` expects.
+fn nested_bound_where_clause(bounds: &[(Type, Ident)], functor_params: &[Ident], target_idents: &[Ident]) -> TokenStream2 {
+    if bounds.is_empty() {
+        return quote!();
+    }
+    let predicates = bounds.iter().map(|(ty, param)| {
+        let idx = functor_params.iter().position(|p| p == param).unwrap();
+        let target = &target_idents[idx];
+        let substituted = generate_map::type_substitute_param(ty, param, target);
+        quote!(#ty: ::functor_derive::Functor<#param, Target<#target> = #substituted>)
+    });
+    quote!(where #(#predicates),*)
+}
+
+/// Removes duplicate entries, comparing types by their token representation
+/// since `syn::Type` has no useful `Eq` impl for our purposes (e.g. it's
+/// sensitive to irrelevant span information).
+fn dedup_bounds(bounds: &mut Vec<(Type, Ident)>) {
+    let mut seen = HashSet::new();
+    bounds.retain(|(ty, param)| seen.insert((quote!(#ty).to_string(), param.to_string())));
+}